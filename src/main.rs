@@ -1,4 +1,5 @@
-use std::{env, fs, process};
+use std::{env, fmt, fs, process};
+use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::fmt::{Debug};
 use std::fs::{File, metadata};
@@ -7,7 +8,6 @@ use std::path::{Path, PathBuf};
 
 struct CompileTarget {
     vm_file: String,
-    asm_file: String,
     static_name: String,
 }
 
@@ -15,13 +15,44 @@ impl CompileTarget {
     fn new(parent_dir: &String, file_name: &String) -> CompileTarget {
         let static_name: String = file_name.to_string();
         let vm_file = String::from(Path::new(parent_dir).join(file_name.to_owned() + ".vm").to_str().unwrap());
-        let asm_file = String::from(Path::new(parent_dir).join(file_name.to_owned() + ".asm").to_str().unwrap());
 
-        CompileTarget { vm_file, asm_file, static_name }
+        CompileTarget { vm_file, static_name }
     }
 }
 
-fn parse_args(args: &[String]) -> Result<Vec<CompileTarget>, Box<dyn std::error::Error>> {
+// What `parse_args` resolved the command line down to: the files to
+// translate, where to write the result, and the flags that affect
+// translation (the directory-linked bootstrap can be turned off; the
+// result can go to stdout instead of a file).
+struct CliOptions {
+    targets: Vec<CompileTarget>,
+    asm_out: String,
+    bootstrap: bool,
+    to_stdout: bool,
+    check_expected: Option<String>,
+    listing: bool,
+}
+
+fn usage(program: &str) -> String {
+    format!(
+        "Usage: {program} [options] <FILE.vm|DIR>\n\n\
+        Options:\n\
+        \x20   -o, --output PATH   write the translated assembly to PATH instead of the derived .asm file\n\
+        \x20       --stdout        print the translated assembly to stdout instead of writing a file\n\
+        \x20       --no-bootstrap  suppress the Sys.init bootstrap code when translating a directory\n\
+        \x20       --check PATH    run the input through the built-in interpreter and compare its final stack\n\
+        \x20                       against the whitespace-separated integers in PATH (a .expected file),\n\
+        \x20                       instead of writing .asm\n\
+        \x20       --listing       interleave each VM source line as a comment above the asm it produced\n\
+        \x20   -h, --help          print this help menu\n"
+    )
+}
+
+// Hand-rolled flag parsing (no external crate: this repo has no Cargo.toml
+// to pull one in). On `-h`/`--help` or a parse failure this prints the
+// usage string and exits the process directly, so callers only ever see a
+// successfully resolved `CliOptions`.
+fn parse_args(args: &[String]) -> CliOptions {
     fn g(path: &String) -> CompileTarget {
         let path = Path::new(&path);
         let parent = String::from(path.parent().unwrap().to_str().unwrap());
@@ -29,24 +60,132 @@ fn parse_args(args: &[String]) -> Result<Vec<CompileTarget>, Box<dyn std::error:
         CompileTarget::new(&parent, &file_stem)
     }
 
-    if args.len() < 1 {
-        return Err(String::from("not enough arguments").into());
+    let program = &args[0];
+    let fail = |msg: &str| -> ! {
+        eprintln!("{msg}\n{}", usage(program));
+        process::exit(1);
+    };
+    let take_value = |flag: &str, it: &mut std::slice::Iter<String>| -> String {
+        it.next().cloned().unwrap_or_else(|| fail(&format!("{flag} expects an argument")))
+    };
+
+    let mut output: Option<String> = None;
+    let mut to_stdout = false;
+    let mut no_bootstrap = false;
+    let mut check_expected: Option<String> = None;
+    let mut listing = false;
+    let mut free: Option<String> = None;
+
+    let mut it = args[1..].iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print!("{}", usage(program));
+                process::exit(0);
+            }
+            "-o" | "--output" => output = Some(take_value(arg, &mut it)),
+            "--stdout" => to_stdout = true,
+            "--no-bootstrap" => no_bootstrap = true,
+            "--check" => check_expected = Some(take_value(arg, &mut it)),
+            "--listing" => listing = true,
+            arg if arg.starts_with('-') => fail(&format!("unrecognized option '{arg}'")),
+            arg if free.is_none() => free = Some(arg.to_string()),
+            arg => fail(&format!("unexpected extra argument '{arg}'")),
+        }
     }
 
-    let canonical_p = String::from(fs::canonicalize(PathBuf::from(&args[1]))?.as_path().to_str().unwrap());
+    let free = free.unwrap_or_else(|| fail("missing input .vm file or directory"));
 
-    let files = if metadata(&canonical_p).unwrap().is_dir() {
-        fs::read_dir(&canonical_p).unwrap()
-            .map(|item| item.unwrap().path())
-            .filter(|item| item.is_file() && item.extension().unwrap() == "vm")
-            .map(|path| g(&path.to_str().unwrap().to_string())).collect()
+    let pbf = PathBuf::from(&free);
+    let canonical_p = fs::canonicalize(&pbf).unwrap_or_else(|err| {
+        eprintln!("{free}: {err}");
+        process::exit(1);
+    });
+    let canonical_p = String::from(canonical_p.as_path().to_str().unwrap());
+
+    let is_dir = metadata(&canonical_p).unwrap_or_else(|err| {
+        eprintln!("{canonical_p}: {err}");
+        process::exit(1);
+    }).is_dir();
+    let (targets, derived_asm_out) = if is_dir {
+        let asm_out = Path::new(&canonical_p).join(pbf.file_stem().unwrap().to_str().unwrap().to_string() + ".asm")
+            .to_str().unwrap().to_string();
+        let entries = fs::read_dir(&canonical_p).unwrap_or_else(|err| {
+            eprintln!("{canonical_p}: {err}");
+            process::exit(1);
+        });
+        let targets = entries
+            .map(|item| item.unwrap_or_else(|err| {
+                eprintln!("{canonical_p}: {err}");
+                process::exit(1);
+            }).path())
+            .filter(|item| item.is_file() && item.extension().is_some_and(|e| e == "vm"))
+            .map(|path| g(&path.to_str().unwrap().to_string())).collect();
+        (targets, asm_out)
     } else {
-        vec![g(&String::from(&canonical_p))]
+        let asm_out = Path::new(&canonical_p).parent().unwrap()
+            .join(pbf.file_stem().unwrap().to_str().unwrap().to_string() + ".asm")
+            .to_str().unwrap().to_string();
+        (vec![g(&canonical_p)], asm_out)
     };
 
-    Ok(files)
+    let asm_out = output.unwrap_or(derived_asm_out);
+    let bootstrap = is_dir && !no_bootstrap;
+
+    CliOptions { targets, asm_out, bootstrap, to_stdout, check_expected, listing }
+}
+
+
+#[derive(Debug)]
+enum TranslateErrorKind {
+    UnknownCommand(String),
+    UnknownSegment(String),
+    MissingOperand,
+    BadIndex(String),
+    Runtime(String),
+    Io(String),
+}
+
+impl fmt::Display for TranslateErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TranslateErrorKind::UnknownCommand(cmd) => write!(f, "unknown command '{cmd}'"),
+            TranslateErrorKind::UnknownSegment(seg) => write!(f, "unknown segment '{seg}'"),
+            TranslateErrorKind::MissingOperand => write!(f, "missing operand"),
+            TranslateErrorKind::BadIndex(idx) => write!(f, "'{idx}' is not a valid index"),
+            TranslateErrorKind::Runtime(msg) => write!(f, "{msg}"),
+            TranslateErrorKind::Io(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+// A parse failure located at the source line it came from, so a malformed
+// .vm file reports where it broke instead of panicking blind. `text` holds
+// the offending line verbatim so the message reads like
+// `foo.vm:42: unknown segment 'fp' in 'push fp 3'`.
+#[derive(Debug)]
+struct TranslateError {
+    file: String,
+    line: usize,
+    col: Option<usize>,
+    text: Option<String>,
+    kind: TranslateErrorKind,
+}
+
+impl fmt::Display for TranslateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.col {
+            Some(col) => write!(f, "{}:{}:{}: {}", self.file, self.line, col, self.kind)?,
+            None => write!(f, "{}:{}: {}", self.file, self.line, self.kind)?,
+        }
+        if let Some(text) = &self.text {
+            write!(f, " in '{text}'")?;
+        }
+        Ok(())
+    }
 }
 
+impl std::error::Error for TranslateError {}
 
 #[derive(Debug)]
 enum VMCommand {
@@ -61,6 +200,10 @@ enum VMCommand {
     CCall(String, u16),
 }
 
+fn is_valid_segment(seg: &str) -> bool {
+    matches!(seg, "constant" | "local" | "argument" | "this" | "that" | "pointer" | "temp" | "static")
+}
+
 fn get_mem_seg(seg: &str) -> Result<&str, String> {
     Ok(match seg {
         "local" => "LCL",
@@ -100,26 +243,114 @@ fn get_cmp_op(comm: &str) -> Result<&str, String> {
 
 
 impl VMCommand {
-    fn new(s: &String) -> Result<VMCommand, String> {
-        let v: Vec<&str> = s.as_str().trim().split(" ").collect();
+    fn new(s: &str, file: &str, line: usize) -> Result<VMCommand, TranslateError> {
+        let err = |kind: TranslateErrorKind| {
+            TranslateError { file: file.to_string(), line, col: None, text: Some(s.trim().to_string()), kind }
+        };
+
+        let v: Vec<&str> = s.trim().split(" ").collect();
+        let operand = |i: usize| v.get(i).map(|o| o.trim()).ok_or_else(|| err(TranslateErrorKind::MissingOperand));
+        let index = |i: usize| -> Result<u16, TranslateError> {
+            let raw = operand(i)?;
+            raw.parse::<u16>().map_err(|_| err(TranslateErrorKind::BadIndex(raw.to_string())))
+        };
+
+        let segment = |i: usize| -> Result<&str, TranslateError> {
+            let seg = operand(i)?;
+            if is_valid_segment(seg) { Ok(seg) } else { Err(err(TranslateErrorKind::UnknownSegment(seg.to_string()))) }
+        };
+
         match v[0] {
-            "push" => return Ok(VMCommand::CPush(String::from(v[1].trim()), v[2].trim().parse::<u16>().unwrap())),
-            "pop" => return Ok(VMCommand::CPop(String::from(v[1].trim()), v[2].trim().parse::<u16>().unwrap())),
+            "push" => Ok(VMCommand::CPush(segment(1)?.to_string(), index(2)?)),
+            "pop" => Ok(VMCommand::CPop(segment(1)?.to_string(), index(2)?)),
             "sub" | "add" | "and" | "or" | "neg" | "not" | "eq" | "gt" | "lt" =>
                 Ok(VMCommand::CArithmetic(String::from(v[0]), 0)),
-            s => return Err(String::from("unimplemented vm command type: ") + s),
+            "label" => Ok(VMCommand::CLabel(operand(1)?.to_string(), 0)),
+            "goto" => Ok(VMCommand::CGoto(operand(1)?.to_string(), 0)),
+            "if-goto" => Ok(VMCommand::CIf(operand(1)?.to_string(), 0)),
+            "function" => Ok(VMCommand::CFunction(operand(1)?.to_string(), index(2)?)),
+            "return" => Ok(VMCommand::CReturn(String::from(v[0]), 0)),
+            "call" => Ok(VMCommand::CCall(operand(1)?.to_string(), index(2)?)),
+            cmd => Err(err(TranslateErrorKind::UnknownCommand(cmd.to_string()))),
         }
     }
 
-    fn to_asm(&self, jump_count: &mut u64, static_name: &str) -> Result<String, String> {
+    fn to_asm(&self, jump_count: &mut u64, static_name: &str, curr_fname: &str) -> Result<String, String> {
         Ok(match &*self {
             VMCommand::CArithmetic(seg, _) => VMCommand::carithmetic2asm(seg, jump_count)?,
             VMCommand::CPush(seg, idx) => VMCommand::cpush2asm(seg, idx, static_name)?,
             VMCommand::CPop(seg, idx) => VMCommand::cpop2asm(seg, idx, static_name)?,
-            _ => return Err(String::from("unmatched vmcommand ")),
+            VMCommand::CLabel(label, _) => VMCommand::clabel2asm(label, curr_fname)?,
+            VMCommand::CGoto(label, _) => VMCommand::cgoto2asm(label, curr_fname)?,
+            VMCommand::CIf(label, _) => VMCommand::cif2asm(label, curr_fname)?,
+            VMCommand::CFunction(fname, n_vars) => VMCommand::cfunction2asm(fname, n_vars)?,
+            VMCommand::CReturn(_, _) => VMCommand::creturn2asm()?,
+            VMCommand::CCall(fname, n_args) => VMCommand::ccall2asm(fname, n_args, jump_count)?,
         })
     }
 
+    fn clabel2asm(label: &str, func_name: &str) -> Result<String, String> {
+        Ok(format!("({func_name}${label})"))
+    }
+
+    fn cgoto2asm(label: &str, func_name: &str) -> Result<String, String> {
+        Ok(format!("@{func_name}${label}\n0;JMP"))
+    }
+
+    fn cif2asm(label: &str, func_name: &str) -> Result<String, String> {
+        Ok(format!("@SP\nAM=M-1\nD=M\n@{func_name}${label}\nD;JNE"))
+    }
+
+    fn cfunction2asm(func_name: &str, n_vars: &u16) -> Result<String, String> {
+        Ok(match n_vars {
+            0 => format!("({func_name})"),
+            n_vars => format!(
+                "({func_name})\n\
+                @{n_vars}\n\
+                D=A\n\
+                ({func_name}_rep)\n\
+                @SP\n\
+                AM=M+1\n\
+                A=A-1\n\
+                M=0\n\
+                @{func_name}_rep\n\
+                D=D-1;JGT"
+            ),
+        })
+    }
+
+    fn ccall2asm(fname: &str, n_args: &u16, jump_count: &mut u64) -> Result<String, String> {
+        let return_addr = format!("{fname}$ret.{jump_count}");
+        let asm_stk_push = "@SP\nAM=M+1\nA=A-1\nM=D";
+        let s = format!(
+            "@{return_addr}\nD=A\n{asm_stk_push}\n\
+            @LCL\nD=M\n{asm_stk_push}\n\
+            @ARG\nD=M\n{asm_stk_push}\n\
+            @THIS\nD=M\n{asm_stk_push}\n\
+            @THAT\nD=M\n{asm_stk_push}\n\
+            @SP\nD=M\n@LCL\nM=D\n\
+            @5\nD=D-A\n@{n_args}\nD=D-A\n@ARG\nM=D\n\
+            @{fname}\n0;JMP\n\
+            ({return_addr})"
+        );
+        *jump_count += 1;
+        Ok(s)
+    }
+
+    fn creturn2asm() -> Result<String, String> {
+        // frame = R13, retAddr = R14
+        Ok("\
+            @LCL\nD=M\n@R13\nM=D\n\
+            @5\nA=D-A\nD=M\n@R14\nM=D\n\
+            @SP\nAM=M-1\nD=M\n@ARG\nA=M\nM=D\n\
+            @ARG\nD=M\n@SP\nM=D+1\n\
+            @R13\nAM=M-1\nD=M\n@THAT\nM=D\n\
+            @R13\nAM=M-1\nD=M\n@THIS\nM=D\n\
+            @R13\nAM=M-1\nD=M\n@ARG\nM=D\n\
+            @R13\nAM=M-1\nD=M\n@LCL\nM=D\n\
+            @R14\nA=M\n0;JMP".to_string())
+    }
+
     fn cpush2asm(seg: &str, idx: &u16, static_name: &str) -> Result<String, String> {
         let s = match (seg, idx) {
             ("constant", idx) => format!("@{idx}\nD=A"),
@@ -137,8 +368,8 @@ impl VMCommand {
 
     fn cpop2asm(seg: &str, idx: &u16, static_name: &str) -> Result<String, String> {
         let s = match (seg, idx) {
-            ("pointer", 0) => "@THISD=A".to_string(),
-            ("pointer", 1) => "@THATD=A".to_string(),
+            ("pointer", 0) => "@THIS\nD=A".to_string(),
+            ("pointer", 1) => "@THAT\nD=A".to_string(),
             ("temp", idx) => format!("@5\nD=A\n@{idx}\nD=D+A"),
             ("static", idx) => format!("@{static_name}.{idx}\nD=A"),
             (seg, idx) => {
@@ -146,7 +377,7 @@ impl VMCommand {
                 format!("@{mem_seg}\nD=M\n@{idx}\nD=D+A")
             }
         };
-        Ok(s + "\n@R15\nM=D\n@SP\nAM=M-1\nD=M\nR15\nA=M\nM=D")
+        Ok(s + "\n@R15\nM=D\n@SP\nAM=M-1\nD=M\n@R15\nA=M\nM=D")
     }
 
     fn carithmetic2asm(comm: &str, jump_count: &mut u64) -> Result<String, String> {
@@ -161,46 +392,480 @@ impl VMCommand {
             }
             "eq" | "gt" | "lt" => {
                 let op = get_cmp_op(comm)?;
-                format!("@R15\nM=-1\n@SP\nAM=M-1\nD=M\nA=A-1\nD=M-D\n@JMP_FALSE{jump_count}\nD;{op}\n\
-                @R15\nM=0\n(JMP_FALSE{jump_count})\n@R15\nD=M\n@SP\nA=M-1\nM=D")
+                let s = format!("@R15\nM=-1\n@SP\nAM=M-1\nD=M\nA=A-1\nD=M-D\n@JMP_FALSE{jump_count}\nD;{op}\n\
+                @R15\nM=0\n(JMP_FALSE{jump_count})\n@R15\nD=M\n@SP\nA=M-1\nM=D");
+                *jump_count += 1;
+                s
             }
             comm => return Err(String::from(format!("unimplemented arithmetic: {comm}"))),
         })
     }
 }
 
-fn translate_vm(target_vm: &CompileTarget) -> Result<String, String> {
-    println!("process {}.write to {}", &target_vm.vm_file, &target_vm.asm_file);
-    let file = File::open(&target_vm.vm_file).unwrap();
+fn translate_vm(target_vm: &CompileTarget, asm_out: &str, jump_count: u64, listing: bool) -> Result<(String, u64), TranslateError> {
+    eprintln!("process {} write to {}", &target_vm.vm_file, asm_out);
+    let file = File::open(&target_vm.vm_file).map_err(|err| io_err(&target_vm.vm_file, 0, err))?;
     let reader = BufReader::new(file);
 
-    let mut jump_count: u64 = 0;
+    let mut jump_count: u64 = jump_count;
+    let mut func_name = "System".to_string();
 
     let mut result_asm = String::new();
-    for line in reader.lines() {
-        let unwrapped = line.unwrap();
+    for (line_no, line) in reader.lines().enumerate() {
+        let unwrapped = line.map_err(|err| io_err(&target_vm.vm_file, line_no + 1, err))?;
         let line_ = String::from(&unwrapped[..unwrapped.find("//").unwrap_or(unwrapped.len())]);
-        if line_.len() > 0 {
-            let vm_ = VMCommand::new(&line_)?;
-            let asm_ = vm_.to_asm(&mut jump_count, target_vm.static_name.as_str())?;
+        let trimmed = line_.trim();
+        if trimmed.len() > 0 {
+            let vm_ = VMCommand::new(&line_, &target_vm.vm_file, line_no + 1)?;
+            let asm_ = vm_.to_asm(&mut jump_count, target_vm.static_name.as_str(), func_name.as_str())
+                .map_err(|err| TranslateError {
+                    file: target_vm.vm_file.clone(),
+                    line: line_no + 1,
+                    col: None,
+                    text: Some(trimmed.to_string()),
+                    kind: TranslateErrorKind::Runtime(err),
+                })?;
+            if listing {
+                result_asm += &format!("// {trimmed}\n");
+            }
             result_asm += &(asm_ + "\n");
+            if let VMCommand::CFunction(fname, _) = &vm_ {
+                func_name = fname.to_string();
+            }
         }
     }
-    Ok(result_asm)
+    Ok((result_asm, jump_count))
 }
 
+fn bootstrap(jump_count: u64) -> (String, u64) {
+    let stack_base_addr = 256;
+    let cmd = format!("@{stack_base_addr}\nD=A\n@SP\nM=D\n");
+    let mut jump_count = jump_count;
+    let ccall_ = VMCommand::ccall2asm("Sys.init", &0, &mut jump_count).unwrap();
+    (cmd + &ccall_ + "\n", jump_count)
+}
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    let input_vms = parse_args(&args).unwrap_or_else(|err| {
-        println!("Problem parsing arguments: {}", err);
+// Parses a .vm file into its commands without generating any assembly, so
+// the commands can be handed to the interpreter instead.
+fn parse_vm_commands(target_vm: &CompileTarget) -> Result<Vec<VMCommand>, TranslateError> {
+    let file = File::open(&target_vm.vm_file).map_err(|err| io_err(&target_vm.vm_file, 0, err))?;
+    let reader = BufReader::new(file);
+
+    let mut commands = Vec::new();
+    for (line_no, line) in reader.lines().enumerate() {
+        let unwrapped = line.map_err(|err| io_err(&target_vm.vm_file, line_no + 1, err))?;
+        let line_ = String::from(&unwrapped[..unwrapped.find("//").unwrap_or(unwrapped.len())]);
+        if line_.len() > 0 {
+            commands.push(VMCommand::new(&line_, &target_vm.vm_file, line_no + 1)?);
+        }
+    }
+    Ok(commands)
+}
+
+// Hack RAM addresses, matching the layout the asm backend already targets.
+const RAM_SIZE: usize = 32768;
+const SP_ADDR: usize = 0;
+const LCL_ADDR: usize = 1;
+const ARG_ADDR: usize = 2;
+const THIS_ADDR: usize = 3;
+const THAT_ADDR: usize = 4;
+const TEMP_BASE: usize = 5;
+const STATIC_BASE: usize = 16;
+const STACK_BASE: i16 = 256;
+
+// Default local/argument/this/that bases for a program run with no prior
+// `call`, matching the non-overlapping regions the standard .tst test
+// scripts set up by hand (e.g. BasicTest.tst: "set local 300, set argument
+// 400, ..."). Without this, `local 0` and `argument 0` would both resolve
+// to RAM[0] and silently alias SP itself.
+const DEFAULT_LCL: i16 = 300;
+const DEFAULT_ARG: i16 = 400;
+const DEFAULT_THIS: i16 = 3000;
+const DEFAULT_THAT: i16 = 3010;
+
+fn runtime_err(ip: usize, msg: String) -> TranslateError {
+    TranslateError { file: "<interpreter>".to_string(), line: ip + 1, col: None, text: None, kind: TranslateErrorKind::Runtime(msg) }
+}
+
+fn io_err(file: &str, line: usize, err: std::io::Error) -> TranslateError {
+    TranslateError { file: file.to_string(), line, col: None, text: None, kind: TranslateErrorKind::Io(err.to_string()) }
+}
+
+fn seg_addr(seg: &str, idx: u16, ram: &[i16; RAM_SIZE], ip: usize) -> Result<usize, TranslateError> {
+    Ok(match (seg, idx) {
+        ("pointer", 0) => THIS_ADDR,
+        ("pointer", 1) => THAT_ADDR,
+        ("pointer", idx) => return Err(runtime_err(ip, format!("'{idx}' is not a valid pointer index"))),
+        ("temp", idx) => TEMP_BASE + idx as usize,
+        ("static", idx) => STATIC_BASE + idx as usize,
+        ("local", idx) => ram[LCL_ADDR] as usize + idx as usize,
+        ("argument", idx) => ram[ARG_ADDR] as usize + idx as usize,
+        ("this", idx) => ram[THIS_ADDR] as usize + idx as usize,
+        ("that", idx) => ram[THAT_ADDR] as usize + idx as usize,
+        (seg, _) => return Err(runtime_err(ip, format!("unimplemented mem_seg: {seg}"))),
+    })
+}
+
+fn stack_push(ram: &mut [i16; RAM_SIZE], val: i16) {
+    let sp = ram[SP_ADDR] as usize;
+    ram[sp] = val;
+    ram[SP_ADDR] += 1;
+}
+
+fn stack_pop(ram: &mut [i16; RAM_SIZE], ip: usize) -> Result<i16, TranslateError> {
+    if ram[SP_ADDR] <= STACK_BASE {
+        return Err(runtime_err(ip, "stack underflow".to_string()));
+    }
+    ram[SP_ADDR] -= 1;
+    Ok(ram[ram[SP_ADDR] as usize])
+}
+
+fn run_arithmetic(comm: &str, ram: &mut [i16; RAM_SIZE], ip: usize) -> Result<(), TranslateError> {
+    match comm {
+        "neg" => { let a = stack_pop(ram, ip)?; stack_push(ram, a.wrapping_neg()); }
+        "not" => { let a = stack_pop(ram, ip)?; stack_push(ram, !a); }
+        "add" => { let b = stack_pop(ram, ip)?; let a = stack_pop(ram, ip)?; stack_push(ram, a.wrapping_add(b)); }
+        "sub" => { let b = stack_pop(ram, ip)?; let a = stack_pop(ram, ip)?; stack_push(ram, a.wrapping_sub(b)); }
+        "and" => { let b = stack_pop(ram, ip)?; let a = stack_pop(ram, ip)?; stack_push(ram, a & b); }
+        "or" => { let b = stack_pop(ram, ip)?; let a = stack_pop(ram, ip)?; stack_push(ram, a | b); }
+        "eq" | "gt" | "lt" => {
+            let b = stack_pop(ram, ip)?;
+            let a = stack_pop(ram, ip)?;
+            let result = match comm {
+                "eq" => a == b,
+                "gt" => a > b,
+                _ => a < b,
+            };
+            stack_push(ram, if result { -1 } else { 0 });
+        }
+        comm => return Err(runtime_err(ip, format!("unimplemented arithmetic: {comm}"))),
+    }
+    Ok(())
+}
+
+// Runs `commands` against a simulated Hack RAM and returns the final stack
+// contents, mirroring the same segment layout and call/return protocol the
+// asm backend emits. `max_steps` bounds execution: a program that finishes
+// (falls off the end of `commands`) returns immediately, while one that
+// never halts (e.g. the `label LOOP / goto LOOP` idiom real compiled
+// programs end on, since the Hack CPU has no HALT) just stops after
+// `max_steps` and reports the stack at that point.
+fn run(commands: &[VMCommand], max_steps: usize) -> Result<Vec<i16>, TranslateError> {
+    let mut function_table: HashMap<&str, usize> = HashMap::new();
+    let mut label_table: HashMap<(&str, &str), usize> = HashMap::new();
+    let mut owner: Vec<&str> = Vec::with_capacity(commands.len());
+
+    let mut func_name = "System";
+    for (i, cmd) in commands.iter().enumerate() {
+        if let VMCommand::CFunction(fname, _) = cmd {
+            func_name = fname.as_str();
+            function_table.insert(func_name, i);
+        }
+        owner.push(func_name);
+        if let VMCommand::CLabel(label, _) = cmd {
+            label_table.insert((func_name, label.as_str()), i);
+        }
+    }
+
+    let mut ram = [0i16; RAM_SIZE];
+    ram[SP_ADDR] = STACK_BASE;
+    ram[LCL_ADDR] = DEFAULT_LCL;
+    ram[ARG_ADDR] = DEFAULT_ARG;
+    ram[THIS_ADDR] = DEFAULT_THIS;
+    ram[THAT_ADDR] = DEFAULT_THAT;
+
+    let mut ip = 0usize;
+    let mut steps = 0usize;
+    while ip < commands.len() && steps < max_steps {
+        steps += 1;
+
+        let mut next_ip = ip + 1;
+        match &commands[ip] {
+            VMCommand::CArithmetic(comm, _) => run_arithmetic(comm, &mut ram, ip)?,
+            VMCommand::CPush(seg, idx) => {
+                let val = if seg == "constant" { *idx as i16 } else { ram[seg_addr(seg, *idx, &ram, ip)?] };
+                stack_push(&mut ram, val);
+            }
+            VMCommand::CPop(seg, idx) => {
+                let addr = seg_addr(seg, *idx, &ram, ip)?;
+                let val = stack_pop(&mut ram, ip)?;
+                ram[addr] = val;
+            }
+            VMCommand::CLabel(_, _) => {}
+            VMCommand::CGoto(label, _) => {
+                next_ip = *label_table.get(&(owner[ip], label.as_str()))
+                    .ok_or_else(|| runtime_err(ip, format!("label '{label}' not found")))?;
+            }
+            VMCommand::CIf(label, _) => {
+                if stack_pop(&mut ram, ip)? != 0 {
+                    next_ip = *label_table.get(&(owner[ip], label.as_str()))
+                        .ok_or_else(|| runtime_err(ip, format!("label '{label}' not found")))?;
+                }
+            }
+            VMCommand::CFunction(_, n_vars) => {
+                for _ in 0..*n_vars {
+                    stack_push(&mut ram, 0);
+                }
+            }
+            VMCommand::CCall(fname, n_args) => {
+                let return_addr = next_ip as i16;
+                let (lcl, arg, this, that) = (ram[LCL_ADDR], ram[ARG_ADDR], ram[THIS_ADDR], ram[THAT_ADDR]);
+                stack_push(&mut ram, return_addr);
+                stack_push(&mut ram, lcl);
+                stack_push(&mut ram, arg);
+                stack_push(&mut ram, this);
+                stack_push(&mut ram, that);
+                ram[ARG_ADDR] = ram[SP_ADDR] - 5 - *n_args as i16;
+                ram[LCL_ADDR] = ram[SP_ADDR];
+                next_ip = *function_table.get(fname.as_str())
+                    .ok_or_else(|| runtime_err(ip, format!("call to unknown function '{fname}'")))?;
+            }
+            VMCommand::CReturn(_, _) => {
+                let frame = ram[LCL_ADDR] as usize;
+                let return_addr = ram[frame - 5];
+                let retval = stack_pop(&mut ram, ip)?;
+                ram[ram[ARG_ADDR] as usize] = retval;
+                ram[SP_ADDR] = ram[ARG_ADDR] + 1;
+                ram[THAT_ADDR] = ram[frame - 1];
+                ram[THIS_ADDR] = ram[frame - 2];
+                ram[ARG_ADDR] = ram[frame - 3];
+                ram[LCL_ADDR] = ram[frame - 4];
+                next_ip = return_addr as usize;
+            }
+        }
+        ip = next_ip;
+    }
+
+    Ok(ram[STACK_BASE as usize..ram[SP_ADDR] as usize].to_vec())
+}
+
+// A single decoded Hack instruction, produced by `assemble` from the asm
+// text `to_asm` emits. Kept as owned strings (matching this file's style
+// elsewhere) rather than bit-packed, since this is a diagnostic backend,
+// not a perf-sensitive one.
+#[derive(Debug)]
+enum AsmInstr {
+    A(i16),
+    C { dest: String, comp: String, jump: String },
+}
+
+// Assembles Hack asm text into instructions, resolving the predefined
+// SP/LCL/ARG/THIS/THAT/R0-R15/SCREEN/KBD symbols, `(LABEL)` targets, and
+// user variables (assigned RAM addresses starting at 16, in order of first
+// use) exactly like the real two-pass Hack assembler.
+fn assemble(asm: &str) -> Vec<AsmInstr> {
+    let mut labels: HashMap<&str, usize> = HashMap::new();
+    let mut instr_count = 0usize;
+    for raw in asm.lines() {
+        let line = raw.trim();
+        if line.is_empty() || line.starts_with("//") {
+            continue;
+        }
+        match line.strip_prefix('(').and_then(|s| s.strip_suffix(')')) {
+            Some(label) => { labels.insert(label, instr_count); }
+            None => instr_count += 1,
+        }
+    }
+
+    let mut symbols: HashMap<String, i16> = HashMap::from([
+        ("SP".to_string(), 0), ("LCL".to_string(), 1), ("ARG".to_string(), 2),
+        ("THIS".to_string(), 3), ("THAT".to_string(), 4),
+        ("SCREEN".to_string(), 16384), ("KBD".to_string(), 24576),
+    ]);
+    for i in 0..16 {
+        symbols.insert(format!("R{i}"), i);
+    }
+    let mut next_var: i16 = 16;
+
+    let mut instrs = Vec::with_capacity(instr_count);
+    for raw in asm.lines() {
+        let line = raw.trim();
+        if line.is_empty() || line.starts_with("//") || line.starts_with('(') {
+            continue;
+        }
+        if let Some(sym) = line.strip_prefix('@') {
+            let addr = if let Ok(n) = sym.parse::<i16>() {
+                n
+            } else if let Some(&addr) = labels.get(sym) {
+                addr as i16
+            } else if let Some(&addr) = symbols.get(sym) {
+                addr
+            } else {
+                let addr = next_var;
+                symbols.insert(sym.to_string(), addr);
+                next_var += 1;
+                addr
+            };
+            instrs.push(AsmInstr::A(addr));
+        } else {
+            let (dest, rest) = line.split_once('=').map_or((String::new(), line), |(d, r)| (d.to_string(), r));
+            let (comp, jump) = rest.split_once(';').map_or((rest.to_string(), String::new()), |(c, j)| (c.to_string(), j.to_string()));
+            instrs.push(AsmInstr::C { dest, comp, jump });
+        }
+    }
+    instrs
+}
+
+fn eval_comp(comp: &str, a: i16, d: i16, m: i16, pc: usize) -> Result<i16, TranslateError> {
+    Ok(match comp {
+        "0" => 0,
+        "1" => 1,
+        "-1" => -1,
+        "D" => d,
+        "A" => a,
+        "M" => m,
+        "!D" => !d,
+        "!A" => !a,
+        "!M" => !m,
+        "-D" => d.wrapping_neg(),
+        "-A" => a.wrapping_neg(),
+        "-M" => m.wrapping_neg(),
+        "D+1" => d.wrapping_add(1),
+        "A+1" => a.wrapping_add(1),
+        "M+1" => m.wrapping_add(1),
+        "D-1" => d.wrapping_sub(1),
+        "A-1" => a.wrapping_sub(1),
+        "M-1" => m.wrapping_sub(1),
+        "D+A" => d.wrapping_add(a),
+        "D+M" | "M+D" => d.wrapping_add(m),
+        "D-A" => d.wrapping_sub(a),
+        "D-M" => d.wrapping_sub(m),
+        "A-D" => a.wrapping_sub(d),
+        "M-D" => m.wrapping_sub(d),
+        "D&A" => d & a,
+        "D&M" | "M&D" => d & m,
+        "D|A" => d | a,
+        "D|M" | "M|D" => d | m,
+        comp => return Err(runtime_err(pc, format!("unknown comp mnemonic '{comp}'"))),
+    })
+}
+
+// Runs assembled Hack instructions against a simulated CPU (A/D registers,
+// RAM, PC), seeded the same way `run` seeds its interpreter so the two
+// backends' final stacks are directly comparable. This is what lets
+// `run_check` diff the code generator's output against the interpreter
+// instead of trusting `to_asm` unexercised.
+fn run_asm(instrs: &[AsmInstr], max_steps: usize) -> Result<Vec<i16>, TranslateError> {
+    let mut ram = [0i16; RAM_SIZE];
+    ram[SP_ADDR] = STACK_BASE;
+    ram[LCL_ADDR] = DEFAULT_LCL;
+    ram[ARG_ADDR] = DEFAULT_ARG;
+    ram[THIS_ADDR] = DEFAULT_THIS;
+    ram[THAT_ADDR] = DEFAULT_THAT;
+
+    let mut a_reg: i16 = 0;
+    let mut d_reg: i16 = 0;
+    let mut pc = 0usize;
+    let mut steps = 0usize;
+    while pc < instrs.len() && steps < max_steps {
+        steps += 1;
+        match &instrs[pc] {
+            AsmInstr::A(addr) => {
+                a_reg = *addr;
+                pc += 1;
+            }
+            AsmInstr::C { dest, comp, jump } => {
+                let write_addr = a_reg as usize;
+                let value = eval_comp(comp, a_reg, d_reg, ram[write_addr], pc)?;
+                if dest.contains('A') { a_reg = value; }
+                if dest.contains('D') { d_reg = value; }
+                if dest.contains('M') { ram[write_addr] = value; }
+
+                let should_jump = match jump.as_str() {
+                    "" => false,
+                    "JGT" => value > 0,
+                    "JEQ" => value == 0,
+                    "JGE" => value >= 0,
+                    "JLT" => value < 0,
+                    "JNE" => value != 0,
+                    "JLE" => value <= 0,
+                    "JMP" => true,
+                    jump => return Err(runtime_err(pc, format!("unknown jump mnemonic '{jump}'"))),
+                };
+                pc = if should_jump { a_reg as usize } else { pc + 1 };
+            }
+        }
+    }
+
+    Ok(ram[STACK_BASE as usize..ram[SP_ADDR] as usize].to_vec())
+}
+
+fn run_check(cli: &CliOptions, expected_path: &str) {
+    let mut commands = Vec::new();
+    let mut asm = String::new();
+    let mut jump_count: u64 = 0;
+    for target in &cli.targets {
+        commands.extend(parse_vm_commands(target).unwrap_or_else(|err| {
+            eprintln!("{err}");
+            process::exit(1);
+        }));
+        let (target_asm, next_jump_count) = translate_vm(target, "<in-memory, for --check>", jump_count, false)
+            .unwrap_or_else(|err| { eprintln!("{err}"); process::exit(1); });
+        jump_count = next_jump_count;
+        asm += &target_asm;
+    }
+
+    let expected: Vec<i16> = fs::read_to_string(expected_path)
+        .unwrap_or_else(|err| { eprintln!("{expected_path}: {err}"); process::exit(1); })
+        .split_whitespace()
+        .map(|tok| tok.parse::<i16>().unwrap_or_else(|err| { eprintln!("{expected_path}: {err}"); process::exit(1); }))
+        .collect();
+
+    let actual = run(&commands, 1_000_000).unwrap_or_else(|err| {
+        eprintln!("{err}");
         process::exit(1);
     });
 
-    for x in input_vms {
-        let result_asm = translate_vm(&x).unwrap();
-        print!("{}", result_asm);
-        let mut file = File::create(&x.asm_file).expect("failed to create an asm file!");
-        file.write_all(result_asm.as_ref()).expect("failed to write asm file!");
+    let asm_actual = run_asm(&assemble(&asm), 1_000_000).unwrap_or_else(|err| {
+        eprintln!("asm backend: {err}");
+        process::exit(1);
+    });
+    if asm_actual != actual {
+        println!("FAIL: asm backend disagrees with the interpreter");
+        println!("  interpreter: {actual:?}");
+        println!("  asm backend: {asm_actual:?}");
+        process::exit(1);
+    }
+
+    if actual == expected {
+        println!("PASS: stack matches {expected_path} ({} values)", actual.len());
+    } else {
+        println!("FAIL: stack does not match {expected_path}");
+        println!("  expected: {expected:?}");
+        println!("  actual:   {actual:?}");
+        process::exit(1);
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let cli = parse_args(&args);
+
+    if let Some(expected_path) = &cli.check_expected {
+        run_check(&cli, expected_path);
+        return;
+    }
+
+    let mut jump_count: u64 = 0;
+    let mut asm = String::new();
+
+    if cli.bootstrap {
+        let (bootstrap_asm, next_jump_count) = bootstrap(jump_count);
+        jump_count = next_jump_count;
+        asm += &bootstrap_asm;
+    }
+
+    for target in &cli.targets {
+        let (result_asm, next_jump_count) = translate_vm(target, &cli.asm_out, jump_count, cli.listing)
+            .unwrap_or_else(|err| { eprintln!("{err}"); process::exit(1); });
+        jump_count = next_jump_count;
+        asm += &result_asm;
+    }
+
+    if cli.to_stdout {
+        print!("{asm}");
+    } else {
+        let mut file = File::create(&cli.asm_out).expect("failed to create an asm file!");
+        file.write_all(asm.as_ref()).expect("failed to write asm file!");
     }
 }